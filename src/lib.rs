@@ -1,10 +1,41 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 use core::cell::UnsafeCell;
 use core::mem::MaybeUninit;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use core::{ptr, fmt};
 
+/// Policy for what happens when a `late_get_ref`/`late_get_mut`-style accessor is reached
+/// without a valid value (uninitialized, or poisoned by a panicking initializer).
+///
+/// Controlled by the `unexpected-panic` and `unexpected-unchecked` cargo features; the
+/// default (neither enabled) keeps the crate's original behaviour of a debug-only assertion
+/// followed by `unreachable_unchecked`.
+#[inline]
+fn late_unexpected(reason: &str) -> ! {
+    #[cfg(feature = "unexpected-panic")]
+    {
+        panic!("late-init: value accessed while {}", reason);
+    }
+
+    #[cfg(all(not(feature = "unexpected-panic"), feature = "unexpected-unchecked"))]
+    {
+        let _ = reason;
+        unsafe {
+            core::hint::unreachable_unchecked()
+        }
+    }
+
+    #[cfg(not(any(feature = "unexpected-panic", feature = "unexpected-unchecked")))]
+    {
+        debug_assert!(false, "late-init: value accessed while {}", reason);
+        unsafe {
+            core::hint::unreachable_unchecked()
+        }
+    }
+}
+
 #[repr(transparent)]
 pub struct LateInitUnchecked<T> {
     inner: UnsafeCell<MaybeUninit<T>>,
@@ -42,17 +73,21 @@ impl<T> LateInitUnchecked<T> {
     }
 
     #[inline]
-    pub fn late_init_mut(&mut self, value: T) {
+    pub fn late_init_mut(&mut self, value: T) -> &mut T {
         unsafe {
             self.late_init(value)
         }
     }
 
     /// Repeated initializations will leak previous values without dropping them.
+    ///
+    /// Returns a mutable reference to the freshly written value.
     #[inline(always)]
-    pub unsafe fn late_init<I: Into<T>>(&self, value: I) {
+    pub unsafe fn late_init<I: Into<T>>(&self, value: I) -> &mut T {
         //*self.inner.get() = MaybeUninit::new(value);
-        ptr::write((*self.inner.get()).as_mut_ptr(), value.into());
+        let ptr = (*self.inner.get()).as_mut_ptr();
+        ptr::write(ptr, value.into());
+        &mut *ptr
     }
 
     #[inline]
@@ -69,6 +104,38 @@ impl<T> LateInitUnchecked<T> {
         }
     }
 
+    /// Moves the stored value out, leaving the cell logically uninitialized and handing
+    /// ownership back to the caller to drop (or re-initialize via [`late_init`](Self::late_init)).
+    #[inline]
+    pub fn late_deinit(&mut self) -> T {
+        unsafe {
+            self.late_take()
+        }
+    }
+
+    /// Moves the stored value out without requiring unique access.
+    ///
+    /// # Safety
+    ///
+    /// The cell must actually be initialized, and must not be accessed again until it is
+    /// re-initialized via [`late_init`](Self::late_init) or [`late_reinit`](Self::late_reinit).
+    #[inline]
+    pub unsafe fn late_take(&self) -> T {
+        ptr::read(self.late_ptr())
+    }
+
+    /// Drops the previously stored value before writing the new one, so repeated
+    /// initialization doesn't leak like a bare [`late_init`](Self::late_init) would.
+    ///
+    /// # Safety
+    ///
+    /// The cell must already be initialized.
+    #[inline]
+    pub unsafe fn late_reinit<I: Into<T>>(&self, value: I) {
+        ptr::drop_in_place(self.late_ptr_mut());
+        self.late_init(value);
+    }
+
     #[inline]
     pub fn late_get_ref(&self) -> &T {
         unsafe {
@@ -105,9 +172,9 @@ impl<T> DerefMut for LateInitUnchecked<T> {
     }
 }
 
-#[repr(transparent)]
 pub struct LateInit<T> {
     inner: UnsafeCell<Option<T>>,
+    poisoned: AtomicBool,
 }
 
 unsafe impl<T: Sync> Sync for LateInit<T> { }
@@ -115,15 +182,19 @@ unsafe impl<T: Send> Send for LateInit<T> { }
 
 #[cfg(feature = "const-default")]
 impl<T> const_default::ConstDefault for LateInit<T> {
-    const DEFAULT: Self = LateInit { inner: UnsafeCell::new(None) };
+    const DEFAULT: Self = LateInit { inner: UnsafeCell::new(None), poisoned: AtomicBool::new(false) };
 }
 
 impl<T: fmt::Debug> fmt::Debug for LateInit<T> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let mut fmt = fmt.debug_tuple("LateInit");
-        match self.late_try_get_ref() {
-            Some(inner) => { fmt.field(inner); },
-            None => { fmt.field(&"<UNINIT>"); },
+        if self.is_poisoned() {
+            fmt.field(&"<POISONED>");
+        } else {
+            match self.late_try_get_ref() {
+                Some(inner) => { fmt.field(inner); },
+                None => { fmt.field(&"<UNINIT>"); },
+            }
         }
         fmt.finish()
     }
@@ -134,6 +205,7 @@ impl<T> LateInit<T> {
     pub const fn new() -> Self {
         Self {
             inner: UnsafeCell::new(None),
+            poisoned: AtomicBool::new(false),
         }
     }
 
@@ -141,21 +213,62 @@ impl<T> LateInit<T> {
     pub const fn with(value: T) -> Self {
         Self {
             inner: UnsafeCell::new(Some(value)),
+            poisoned: AtomicBool::new(false),
         }
     }
 
+    /// Returns `true` if a previous [`late_init_with`](Self::late_init_with) call's closure
+    /// panicked while constructing the value, leaving the cell uninitialized, and no later
+    /// `late_init_with` call has completed since (a successful call clears the flag).
     #[inline]
-    pub fn late_init_mut(&mut self, value: T) {
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Initializes the cell with the result of `f`, without leaving a half-written value
+    /// behind if `f` panics.
+    ///
+    /// If `f` unwinds, the cell is marked poisoned instead of initialized, and subsequent
+    /// accessors (e.g. [`late_get_ref`](Self::late_get_ref)) report the poisoned state
+    /// rather than dereferencing garbage. A later call that completes successfully clears
+    /// the poisoned flag again, since the cell demonstrably holds a good value at that point.
+    pub fn late_init_with(&self, f: impl FnOnce() -> T) {
+        struct PoisonOnUnwind<'a> {
+            poisoned: &'a AtomicBool,
+            armed: bool,
+        }
+
+        impl<'a> Drop for PoisonOnUnwind<'a> {
+            fn drop(&mut self) {
+                if self.armed {
+                    self.poisoned.store(true, Ordering::Release);
+                }
+            }
+        }
+
+        let mut guard = PoisonOnUnwind { poisoned: &self.poisoned, armed: true };
+        let value = f();
+        guard.armed = false;
+        unsafe {
+            self.late_init(value);
+        }
+        self.poisoned.store(false, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn late_init_mut(&mut self, value: T) -> &mut T {
         unsafe {
             self.late_init(value)
         }
     }
 
+    /// Returns a mutable reference to the freshly written value.
     #[inline]
-    pub unsafe fn late_init<I: Into<T>>(&self, value: I) {
+    pub unsafe fn late_init<I: Into<T>>(&self, value: I) -> &mut T {
         let inner = self.late_inner_mut();
         debug_assert!(inner.is_none());
         *inner = Some(value.into());
+        inner.as_mut().unwrap_or_else(|| late_unexpected("just initialized"))
     }
 
     #[inline]
@@ -170,15 +283,6 @@ impl<T> LateInit<T> {
         }
     }
 
-    #[inline]
-    fn late_unexpected() -> ! {
-        // TODO: feature to control unreachableness
-        debug_assert!(false);
-        unsafe {
-            core::hint::unreachable_unchecked()
-        }
-    }
-
     pub fn late_ptr(&self) -> *const T {
         self.late_inner().as_ref().map(|inner| inner as *const _).unwrap_or(ptr::null())
     }
@@ -201,23 +305,29 @@ impl<T> LateInit<T> {
     }
 
     pub fn late_get_ref(&self) -> &T {
+        if self.is_poisoned() {
+            late_unexpected("poisoned");
+        }
         match self.late_try_get_ref() {
             Some(inner) => inner,
-            None => Self::late_unexpected(),
+            None => late_unexpected("uninitialized"),
         }
     }
 
     pub fn late_get_mut(&mut self) -> &mut T {
+        if self.is_poisoned() {
+            late_unexpected("poisoned");
+        }
         match self.late_try_get_mut() {
             Some(inner) => inner,
-            None => Self::late_unexpected(),
+            None => late_unexpected("uninitialized"),
         }
     }
 
     pub unsafe fn late_get_mut_unchecked(&self) -> &mut T {
         match self.late_inner_mut().as_mut() {
             Some(inner) => inner,
-            None => Self::late_unexpected(),
+            None => late_unexpected("uninitialized"),
         }
     }
 }
@@ -237,3 +347,430 @@ impl<T> DerefMut for LateInit<T> {
         self.late_get_mut()
     }
 }
+
+/// Like [`LateInit<T>`](LateInit), but disposes of its value by handing it to a
+/// user-provided closure instead of running `T`'s `Drop` impl.
+///
+/// The closure runs at most once, and only once the cell actually holds a value, when the
+/// cell is deinitialized via [`late_deinit`](Self::late_deinit).
+#[repr(transparent)]
+pub struct LateInitDropBy<T, F: FnOnce(T)> {
+    inner: UnsafeCell<Option<(T, F)>>,
+}
+
+unsafe impl<T: Sync, F: FnOnce(T) + Sync> Sync for LateInitDropBy<T, F> { }
+unsafe impl<T: Send, F: FnOnce(T) + Send> Send for LateInitDropBy<T, F> { }
+
+impl<T: fmt::Debug, F: FnOnce(T)> fmt::Debug for LateInitDropBy<T, F> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut fmt = fmt.debug_tuple("LateInitDropBy");
+        match self.late_try_get_ref() {
+            Some(inner) => { fmt.field(inner); },
+            None => { fmt.field(&"<UNINIT>"); },
+        }
+        fmt.finish()
+    }
+}
+
+impl<T, F: FnOnce(T)> LateInitDropBy<T, F> {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            inner: UnsafeCell::new(None),
+        }
+    }
+
+    #[inline]
+    pub const fn with(value: T, drop_by: F) -> Self {
+        Self {
+            inner: UnsafeCell::new(Some((value, drop_by))),
+        }
+    }
+
+    #[inline]
+    pub fn late_init_mut(&mut self, value: T, drop_by: F) {
+        unsafe {
+            self.late_init(value, drop_by)
+        }
+    }
+
+    #[inline]
+    pub unsafe fn late_init(&self, value: T, drop_by: F) {
+        let inner = self.late_inner_mut();
+        debug_assert!(inner.is_none());
+        *inner = Some((value, drop_by));
+    }
+
+    #[inline]
+    unsafe fn late_inner_mut(&self) -> &mut Option<(T, F)> {
+        &mut *self.inner.get()
+    }
+
+    #[inline]
+    fn late_inner(&self) -> &Option<(T, F)> {
+        unsafe {
+            &*self.inner.get()
+        }
+    }
+
+    #[inline]
+    pub fn late_try_get_ref(&self) -> Option<&T> {
+        self.late_inner().as_ref().map(|(value, _)| value)
+    }
+
+    #[inline]
+    pub fn late_try_get_mut(&mut self) -> Option<&mut T> {
+        unsafe {
+            self.late_inner_mut().as_mut().map(|(value, _)| value)
+        }
+    }
+
+    pub fn late_get_ref(&self) -> &T {
+        match self.late_try_get_ref() {
+            Some(inner) => inner,
+            None => late_unexpected("uninitialized"),
+        }
+    }
+
+    pub fn late_get_mut(&mut self) -> &mut T {
+        match self.late_try_get_mut() {
+            Some(inner) => inner,
+            None => late_unexpected("uninitialized"),
+        }
+    }
+
+    pub unsafe fn late_get_mut_unchecked(&self) -> &mut T {
+        match self.late_inner_mut().as_mut() {
+            Some((inner, _)) => inner,
+            None => late_unexpected("uninitialized"),
+        }
+    }
+
+    /// Moves the stored value out and passes it by value to the registered closure. A no-op
+    /// if the cell is not currently initialized.
+    pub fn late_deinit(&mut self) {
+        if let Some((value, drop_by)) = unsafe { self.late_inner_mut() }.take() {
+            drop_by(value);
+        }
+    }
+}
+
+impl<T, F: FnOnce(T)> Drop for LateInitDropBy<T, F> {
+    #[inline]
+    fn drop(&mut self) {
+        self.late_deinit();
+    }
+}
+
+impl<T, F: FnOnce(T)> Deref for LateInitDropBy<T, F> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.late_get_ref()
+    }
+}
+
+impl<T, F: FnOnce(T)> DerefMut for LateInitDropBy<T, F> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.late_get_mut()
+    }
+}
+
+const LATE_INIT_ONCE_UNINIT: u8 = 0;
+const LATE_INIT_ONCE_INITIALIZING: u8 = 1;
+const LATE_INIT_ONCE_INIT: u8 = 2;
+
+/// A `Sync` one-time initialization cell that tracks its state with an atomic flag, instead
+/// of relying on the caller to synchronize [`late_init`](LateInitUnchecked::late_init) calls
+/// externally.
+///
+/// Concurrent callers racing on [`get_or_init`](Self::get_or_init) or
+/// [`try_init`](Self::try_init) are resolved so that exactly one of them stores its value.
+/// Losing `get_or_init` callers wait for and observe the fully-initialized value; losing
+/// `try_init` callers get their value handed back immediately.
+pub struct LateInitOnce<T> {
+    state: AtomicU8,
+    inner: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Sync> Sync for LateInitOnce<T> { }
+unsafe impl<T: Send> Send for LateInitOnce<T> { }
+
+impl<T: fmt::Debug> fmt::Debug for LateInitOnce<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut fmt = fmt.debug_tuple("LateInitOnce");
+        match self.late_try_get_ref() {
+            Some(inner) => { fmt.field(inner); },
+            None => { fmt.field(&"<UNINIT>"); },
+        }
+        fmt.finish()
+    }
+}
+
+impl<T> LateInitOnce<T> {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(LATE_INIT_ONCE_UNINIT),
+            inner: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    #[inline]
+    pub const fn with(value: T) -> Self {
+        Self {
+            state: AtomicU8::new(LATE_INIT_ONCE_INIT),
+            inner: UnsafeCell::new(MaybeUninit::new(value)),
+        }
+    }
+
+    #[inline]
+    pub fn late_ptr(&self) -> *const T {
+        unsafe {
+            (*self.inner.get()).as_ptr()
+        }
+    }
+
+    #[inline]
+    pub fn late_ptr_mut(&self) -> *mut T {
+        unsafe {
+            (*self.inner.get()).as_mut_ptr()
+        }
+    }
+
+    #[inline]
+    fn wait_while_initializing(&self) {
+        while self.state.load(Ordering::Acquire) == LATE_INIT_ONCE_INITIALIZING {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Returns a reference to the stored value, racing other callers to initialize it with
+    /// `f` if no one has yet. The winner runs `f` and stores its result; every other caller
+    /// waits for the race to settle and then either observes the stored value or, if the
+    /// winner's `f` panicked, re-enters the race itself rather than spinning forever.
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        loop {
+            match self.state.compare_exchange(LATE_INIT_ONCE_UNINIT, LATE_INIT_ONCE_INITIALIZING, Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => {
+                    struct ResetOnUnwind<'a> {
+                        state: &'a AtomicU8,
+                        armed: bool,
+                    }
+
+                    impl<'a> Drop for ResetOnUnwind<'a> {
+                        fn drop(&mut self) {
+                            if self.armed {
+                                self.state.store(LATE_INIT_ONCE_UNINIT, Ordering::Release);
+                            }
+                        }
+                    }
+
+                    let mut guard = ResetOnUnwind { state: &self.state, armed: true };
+                    let value = f();
+                    guard.armed = false;
+                    unsafe {
+                        ptr::write((*self.inner.get()).as_mut_ptr(), value);
+                    }
+                    self.state.store(LATE_INIT_ONCE_INIT, Ordering::Release);
+                    break;
+                },
+                Err(LATE_INIT_ONCE_INIT) => break,
+                Err(_) => {
+                    // Someone else is initializing (or just panicked while doing so). Wait
+                    // for them to settle, then loop back and re-race rather than spinning on
+                    // a state that may have reverted to UNINIT.
+                    self.wait_while_initializing();
+                },
+            }
+        }
+        unsafe {
+            &*self.late_ptr()
+        }
+    }
+
+    /// Attempts to claim the cell for `value`. Returns a reference to the stored value on
+    /// success, or hands `value` back if the slot was already claimed (or is concurrently
+    /// being claimed) by another caller.
+    pub fn try_init(&self, value: T) -> Result<&T, T> {
+        match self.state.compare_exchange(LATE_INIT_ONCE_UNINIT, LATE_INIT_ONCE_INITIALIZING, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                unsafe {
+                    ptr::write((*self.inner.get()).as_mut_ptr(), value);
+                }
+                self.state.store(LATE_INIT_ONCE_INIT, Ordering::Release);
+                Ok(unsafe { &*self.late_ptr() })
+            },
+            Err(_) => Err(value),
+        }
+    }
+
+    #[inline]
+    pub fn late_try_get_ref(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == LATE_INIT_ONCE_INIT {
+            Some(unsafe { &*self.late_ptr() })
+        } else {
+            None
+        }
+    }
+
+    pub fn late_get_ref(&self) -> &T {
+        match self.late_try_get_ref() {
+            Some(inner) => inner,
+            None => late_unexpected("uninitialized"),
+        }
+    }
+}
+
+impl<T> Drop for LateInitOnce<T> {
+    fn drop(&mut self) {
+        if self.state.load(Ordering::Acquire) == LATE_INIT_ONCE_INIT {
+            unsafe {
+                ptr::drop_in_place(self.late_ptr_mut());
+            }
+        }
+    }
+}
+
+impl<T> Deref for LateInitOnce<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.late_get_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn late_init_once_concurrent_get_or_init_agrees_on_one_winner() {
+        let cell = Arc::new(LateInitOnce::<u32>::new());
+        let handles: Vec<_> = (0..8).map(|i| {
+            let cell = Arc::clone(&cell);
+            thread::spawn(move || *cell.get_or_init(|| i))
+        }).collect();
+
+        let winner = *cell.get_or_init(|| 999);
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), winner);
+        }
+    }
+
+    #[test]
+    fn late_init_once_recovers_after_panicking_initializer() {
+        let cell = Arc::new(LateInitOnce::<u32>::new());
+
+        let panicked = Arc::clone(&cell);
+        let result = thread::spawn(move || {
+            panicked.get_or_init(|| panic!("boom"));
+        }).join();
+        assert!(result.is_err());
+
+        // A later caller must be able to complete initialization instead of spinning
+        // forever on the state the panicking initializer left behind.
+        assert_eq!(*cell.get_or_init(|| 42), 42);
+    }
+
+    #[test]
+    fn late_init_with_recovers_after_panicking_initializer() {
+        let cell = LateInit::<u32>::new();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cell.late_init_with(|| panic!("boom"));
+        }));
+        assert!(result.is_err());
+        assert!(cell.is_poisoned());
+
+        cell.late_init_with(|| 42);
+        assert!(!cell.is_poisoned());
+        assert_eq!(*cell.late_get_ref(), 42);
+    }
+
+    #[test]
+    fn late_init_drop_by_runs_closure_on_implicit_drop() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let ran = Rc::new(Cell::new(false));
+        let cell = LateInitDropBy::with(5u32, {
+            let ran = Rc::clone(&ran);
+            move |value| {
+                assert_eq!(value, 5);
+                ran.set(true);
+            }
+        });
+
+        drop(cell);
+        assert!(ran.get());
+    }
+
+    struct DropCounter<'a>(&'a std::cell::Cell<u32>);
+
+    impl<'a> Drop for DropCounter<'a> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn late_init_once_drops_value_on_implicit_drop() {
+        let drops = std::cell::Cell::new(0);
+        let cell = LateInitOnce::<DropCounter>::new();
+        cell.get_or_init(|| DropCounter(&drops));
+        assert_eq!(drops.get(), 0);
+
+        drop(cell);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn late_init_once_drop_is_a_no_op_when_never_initialized() {
+        let drops = std::cell::Cell::new(0);
+        let cell = LateInitOnce::<DropCounter>::new();
+        drop(cell);
+        assert_eq!(drops.get(), 0);
+    }
+
+    #[test]
+    fn late_init_unchecked_deinit_and_take_hand_back_ownership_once() {
+        let drops = std::cell::Cell::new(0);
+        let mut cell = LateInitUnchecked::with(DropCounter(&drops));
+
+        let value = cell.late_deinit();
+        assert_eq!(drops.get(), 0);
+        drop(value);
+        assert_eq!(drops.get(), 1);
+
+        // The cell is now logically uninitialized; re-initializing and taking again must
+        // only run the destructor once more, not twice.
+        cell.late_init_mut(DropCounter(&drops));
+        let value = unsafe { cell.late_take() };
+        assert_eq!(drops.get(), 1);
+        drop(value);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn late_init_unchecked_reinit_drops_the_old_value_first() {
+        let drops = std::cell::Cell::new(0);
+        let cell = LateInitUnchecked::with(DropCounter(&drops));
+
+        unsafe {
+            cell.late_reinit(DropCounter(&drops));
+        }
+        // The first value was dropped by late_reinit itself; the second is still parked in
+        // the cell (LateInitUnchecked never runs Drop implicitly), so take it out explicitly.
+        assert_eq!(drops.get(), 1);
+
+        let value = unsafe { cell.late_take() };
+        drop(value);
+        assert_eq!(drops.get(), 2);
+    }
+}